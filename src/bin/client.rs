@@ -1,9 +1,16 @@
+use std::{path::Path, time::Duration};
+
 use clap::{arg, command, Arg};
-use data_encoding::BASE32_NOPAD;
-use dns_kv::Message;
-use rand::Rng;
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use dns_kv::{Message, SiblingSet, VersionId};
+use ed25519_dalek::SigningKey;
+use rand::{rngs::OsRng, Rng};
+use serde_json::json;
 use simple_dns::{rdata::RData, Name, Packet, PacketFlag, Question, CLASS, TYPE};
-use tokio::net::UdpSocket;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpStream, UdpSocket},
+};
 
 // Easy mode error handling.
 type Result<T> = core::result::Result<T, Error>;
@@ -11,8 +18,91 @@ type Error = Box<dyn std::error::Error>;
 
 const MAX_FQDN: usize = 63;
 
-fn txt_query_record(domain: &str) -> Result<Vec<u8>> {
-    let mut pkt = Packet::new_query(1);
+/// Output mode for `get`/`set`: `Human` prints log-style lines, `Json`
+/// emits a single structured object so the CLI can be embedded in scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Human,
+    Json,
+}
+
+impl Format {
+    fn parse(s: &str) -> Result<Format> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown format '{other}', expected human or json").into()),
+        }
+    }
+}
+
+/// Maximum number of send attempts `exchange` makes before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+/// Timeout for the first attempt; doubles on each retry.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(200);
+/// Overall timeout for the TCP fallback query, so a down/unreachable server
+/// can't hang the client forever the way an unbounded `connect` would.
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct ExchangeError {
+    attempts: u32,
+}
+
+impl std::fmt::Display for ExchangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no matching response received after {} attempts",
+            self.attempts
+        )
+    }
+}
+
+impl std::error::Error for ExchangeError {}
+
+/// Sends `query` (carrying the given `id`) over `sock` to `server`, retrying
+/// with an exponentially growing timeout until a reply whose header ID
+/// matches `id` is received. Replies with a mismatched ID (stray retransmits,
+/// duplicate datagrams from a previous query) are discarded rather than
+/// trusted.
+async fn exchange(sock: &UdpSocket, server: &str, query: &[u8], id: u16) -> Result<Vec<u8>> {
+    let mut timeout = INITIAL_TIMEOUT;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        sock.send_to(query, server).await?;
+
+        loop {
+            let mut buf = [0u8; 4096];
+            let recv = tokio::time::timeout(timeout, sock.recv_from(&mut buf)).await;
+            let (size, _) = match recv {
+                Ok(result) => result?,
+                Err(_) => break, // timed out waiting on this attempt
+            };
+
+            let data = &buf[..size];
+            let packet = match Packet::parse(data) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+
+            if packet.id == id {
+                return Ok(data.to_vec());
+            }
+            tracing::debug!("discarding response with mismatched id {}", packet.id);
+        }
+
+        tracing::debug!("attempt {attempt}/{MAX_ATTEMPTS} timed out, retrying");
+        timeout *= 2;
+    }
+
+    Err(Box::new(ExchangeError {
+        attempts: MAX_ATTEMPTS,
+    }))
+}
+
+fn txt_query_record(domain: &str, id: u16) -> Result<Vec<u8>> {
+    let mut pkt = Packet::new_query(id);
     let q = Question::new(
         Name::new_unchecked(domain),
         TYPE::TXT.into(),
@@ -24,20 +114,72 @@ fn txt_query_record(domain: &str) -> Result<Vec<u8>> {
     Ok(pkt.build_bytes_vec()?)
 }
 
-fn parse_txt_response(data: Vec<u8>) -> Result<String> {
+fn parse_txt_response(data: &[u8]) -> Result<(String, bool)> {
     let mut rv = String::new();
-    let packet = Packet::parse(&data)?;
-    let answer = packet.answers[0].clone();
-    if let RData::TXT(val) = answer.rdata {
-        for (k, _) in val.attributes() {
-            rv += &k;
+    let packet = Packet::parse(data)?;
+    let truncated = packet.has_flags(PacketFlag::TRUNCATION);
+
+    // A truncated reply carries no answers at all (see the server's
+    // `process_query`) — it's purely a signal to retry over TCP.
+    if let Some(answer) = packet.answers.first() {
+        if let RData::TXT(val) = &answer.rdata {
+            // Joins the character-strings in the order the server sent them.
+            rv += &String::try_from(val.clone())?;
         }
     }
-    Ok(rv)
+    Ok((rv, truncated))
+}
+
+/// Re-sends `query` to `server` over TCP, framed with the standard 2-byte
+/// big-endian DNS length prefix, and returns the decoded TXT answer. Used as
+/// the fallback when a UDP reply comes back with the TC bit set. Bounded by
+/// `TCP_TIMEOUT` overall, so a down or firewalled server can't hang the
+/// client forever the way `exchange`'s UDP retries never would.
+async fn tcp_txt_query(server: &str, query: &[u8]) -> Result<String> {
+    match tokio::time::timeout(TCP_TIMEOUT, tcp_txt_query_inner(server, query)).await {
+        Ok(result) => result,
+        Err(_) => Err("tcp query timed out".into()),
+    }
 }
 
-fn a_query_record(domain: &str) -> Result<Vec<u8>> {
-    let mut pkt = Packet::new_query(1);
+async fn tcp_txt_query_inner(server: &str, query: &[u8]) -> Result<String> {
+    let stream = TcpStream::connect(server).await?;
+    let (read_half, write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let mut writer = BufWriter::new(write_half);
+
+    writer.write_all(&(query.len() as u16).to_be_bytes()).await?;
+    writer.write_all(query).await?;
+    writer.flush().await?;
+
+    let mut len_buf = [0u8; 2];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+
+    let (value, _truncated) = parse_txt_response(&data)?;
+    Ok(value)
+}
+
+/// Interprets the A-record answer to a commit query: `true` if the server
+/// accepted the write (`41.41.41.41`), `false` if it rejected it (`0.0.0.0`
+/// — bad signature, wrong owner, or a protocol version mismatch).
+fn parse_a_response(data: &[u8]) -> Result<bool> {
+    let packet = Packet::parse(data)?;
+    let answer = packet
+        .answers
+        .first()
+        .ok_or("missing answer in commit response")?;
+    let RData::A(a) = &answer.rdata else {
+        return Err("unexpected answer type in commit response".into());
+    };
+    Ok(a.address == u32::from_be_bytes([41, 41, 41, 41]))
+}
+
+fn a_query_record(domain: &str, id: u16) -> Result<Vec<u8>> {
+    let mut pkt = Packet::new_query(id);
     let q = Question::new(
         Name::new_unchecked(domain),
         TYPE::A.into(),
@@ -49,8 +191,8 @@ fn a_query_record(domain: &str) -> Result<Vec<u8>> {
     Ok(pkt.build_bytes_vec()?)
 }
 
-fn aaaa_query_record(domain: &str) -> Result<Vec<u8>> {
-    let mut pkt = Packet::new_query(1);
+fn aaaa_query_record(domain: &str, id: u16) -> Result<Vec<u8>> {
+    let mut pkt = Packet::new_query(id);
     let q = Question::new(
         Name::new_unchecked(domain),
         TYPE::AAAA.into(),
@@ -62,36 +204,115 @@ fn aaaa_query_record(domain: &str) -> Result<Vec<u8>> {
     Ok(pkt.build_bytes_vec()?)
 }
 
-async fn get_value(server: &str, key: &str) -> Result<()> {
+async fn get_value(server: &str, key: &str, format: Format) -> Result<()> {
     let sock = UdpSocket::bind("0.0.0.0:0").await?;
 
-    let query = txt_query_record(key)?;
-    let mut incoming = String::new();
-    loop {
-        sock.send_to(&query, server).await?;
-        let mut buf = [0; 4096];
-        let (size, _) = sock.recv_from(&mut buf).await?;
-        let data = buf[..size].to_vec();
-        let data = parse_txt_response(data)?;
-        incoming += &data;
-        if data.len() < 255 {
-            break;
-        }
-    }
+    // The server always answers with the complete value in a single TXT
+    // record — spread across as many character-strings as it takes — or,
+    // if that wouldn't fit in a bare UDP datagram, with the TC bit set and
+    // no answers at all. Either way one exchange (plus, on TC, one TCP
+    // retry, which returns the complete value for any reply of up to 65535
+    // bytes) is enough.
+    let id: u16 = rand::rng().random();
+    let query = txt_query_record(key, id)?;
+    let data = exchange(&sock, server, &query, id).await?;
+    let (chunk, truncated) = parse_txt_response(&data)?;
+
+    let incoming = if truncated {
+        tracing::debug!("response truncated, retrying over tcp");
+        tcp_txt_query(server, &query).await?
+    } else {
+        chunk
+    };
 
     let decoded = BASE32_NOPAD.decode(incoming.as_bytes())?;
-    let message: Message = bincode::deserialize(&decoded)?;
-    tracing::info!("value:\n{}", message.value);
+    let siblings: SiblingSet = bincode::deserialize(&decoded)?;
+
+    if format == Format::Json {
+        let siblings_json: Vec<_> = siblings
+            .siblings
+            .iter()
+            .map(|(version, value)| {
+                json!({"version": HEXLOWER.encode(version), "value": value})
+            })
+            .collect();
+        // `value` is only unambiguous when there's exactly one sibling;
+        // with a conflict (or no value at all) it's null and callers must
+        // look at `siblings` to resolve it themselves.
+        let value = match siblings.siblings.as_slice() {
+            [(_, value)] => Some(value.clone()),
+            _ => None,
+        };
+        println!(
+            "{}",
+            json!({"key": key, "value": value, "siblings": siblings_json})
+        );
+        return Ok(());
+    }
+
+    if siblings.siblings.is_empty() {
+        tracing::info!("no value set for {key}");
+        return Ok(());
+    }
+
+    for (version, value) in &siblings.siblings {
+        tracing::info!("sibling {}:\n{}", HEXLOWER.encode(version), value);
+    }
+    if siblings.siblings.len() > 1 {
+        tracing::warn!(
+            "{} concurrent siblings for {key} — a write must resolve them",
+            siblings.siblings.len()
+        );
+    }
     Ok(())
 }
 
-async fn set_value(server: &str, key: &str, value: &str) -> Result<()> {
+/// Loads this client's Ed25519 identity from `path`, generating and saving
+/// a fresh one on first use. The same key must be reused across `set`s to a
+/// given key, since the server rejects writes from a different signer.
+fn load_or_create_signing_key(path: &Path) -> Result<SigningKey> {
+    if let Ok(bytes) = std::fs::read(path) {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| "identity key file is corrupt")?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    let signing_key = SigningKey::generate(&mut OsRng);
+    std::fs::write(path, signing_key.to_bytes())?;
+    Ok(signing_key)
+}
+
+/// Parses a `--context` value: a comma-separated list of the hex-encoded
+/// `VersionId`s a prior `get` observed. An empty string yields no context
+/// (a blind write).
+fn parse_context(s: &str) -> Result<Vec<VersionId>> {
+    s.split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let bytes = HEXLOWER.decode(s.as_bytes())?;
+            let id: VersionId = bytes
+                .try_into()
+                .map_err(|_| "context entry is not a 16-byte hex VersionId")?;
+            Ok(id)
+        })
+        .collect()
+}
+
+async fn set_value(
+    server: &str,
+    key: &str,
+    value: &str,
+    signing_key: &SigningKey,
+    context: Vec<VersionId>,
+    format: Format,
+) -> Result<()> {
     let id: u16 = rand::rng().random();
     let domain = format!(".{id:x}");
-    let message = Message {
-        key: key.to_string(),
-        value: value.to_string(),
-    };
+    // An empty context is a blind write: the value lands as a new sibling
+    // alongside whatever else is already there. Pass `--context` with the
+    // VersionIds a prior `get` printed to supersede those siblings instead.
+    let message = Message::signed(key.to_string(), value.to_string(), context, signing_key)?;
 
     let sock = UdpSocket::bind("0.0.0.0:0").await?;
 
@@ -100,22 +321,26 @@ async fn set_value(server: &str, key: &str, value: &str) -> Result<()> {
     for chunk in encoded.as_bytes().chunks(MAX_FQDN - domain.len()) {
         let chunk = String::from_utf8(chunk.to_vec()).unwrap();
         let fqdn = format!("{}{}", chunk, domain);
-        let query = aaaa_query_record(&fqdn)?;
-        sock.send_to(&query, server).await?;
-        // TODO: Error handle
-        let mut buf = [0; 4096];
-        let (size, _) = sock.recv_from(&mut buf).await?;
-        let _data = buf[..size].to_vec();
+        let query_id: u16 = rand::rng().random();
+        let query = aaaa_query_record(&fqdn, query_id)?;
+        exchange(&sock, server, &query, query_id).await?;
     }
 
-    let query = a_query_record(&format!("{id:x}"))?;
-    sock.send_to(&query, server).await?;
-    // TODO: Error handle
-    let mut buf = [0; 4096];
-    let (size, _) = sock.recv_from(&mut buf).await?;
-    let _data = buf[..size].to_vec();
+    let query_id: u16 = rand::rng().random();
+    let query = a_query_record(&format!("{id:x}"), query_id)?;
+    let data = exchange(&sock, server, &query, query_id).await?;
 
-    println!("Set the key: \"{}\" on the server!", key);
+    if !parse_a_response(&data)? {
+        return Err(
+            "write rejected by server: bad signature, wrong owner, or protocol version mismatch"
+                .into(),
+        );
+    }
+
+    match format {
+        Format::Human => println!("Set the key: \"{}\" on the server!", key),
+        Format::Json => println!("{}", json!({"key": key, "status": "ok"})),
+    }
 
     Ok(())
 }
@@ -141,21 +366,95 @@ async fn main() -> Result<()> {
                     .value_names(["KEY", "VALUE"]) // give them friendly names in help output
                     .help("Set the KEY to VALUE"),
             )
+            .arg(
+                arg!(--"key-file" <FILE> "Path to this client's Ed25519 identity key")
+                    .default_value("client.key"),
+            )
+            .arg(
+                arg!(--format <FORMAT> "Output format: human or json")
+                    .default_value("human"),
+            )
+            .arg(arg!(--context <VERSIONS> "Comma-separated hex VersionIds (from a prior --get) that this --set supersedes").required(false))
             .get_matches();
 
     let server = matches
         .get_one::<String>("server")
         .expect("server has default");
 
-    let get_key = matches.get_one::<String>("get");
-    if get_key.is_some() {
-        return get_value(server, get_key.unwrap()).await;
-    }
+    let format = Format::parse(
+        matches
+            .get_one::<String>("format")
+            .expect("format has default"),
+    )?;
 
+    let get_key = matches.get_one::<String>("get");
     let set_values = matches.get_many::<String>("set");
-    if set_values.is_some() {
-        let values: Vec<_> = set_values.unwrap().map(|v| v.as_str()).collect();
-        return set_value(server, values[0], values[1]).await;
+
+    let result = if let Some(key) = get_key {
+        get_value(server, key, format).await
+    } else if let Some(set_values) = set_values {
+        let key_file = matches.get_one::<String>("key-file").expect("has default");
+        let signing_key = load_or_create_signing_key(Path::new(key_file))?;
+        let context = match matches.get_one::<String>("context") {
+            Some(s) => parse_context(s)?,
+            None => Vec::new(),
+        };
+        let values: Vec<_> = set_values.map(|v| v.as_str()).collect();
+        set_value(server, values[0], values[1], &signing_key, context, format).await
+    } else {
+        Ok(())
+    };
+
+    if let Err(e) = result {
+        if format == Format::Json {
+            println!("{}", json!({"error": e.to_string()}));
+            return Ok(());
+        }
+        return Err(e);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_context_splits_on_commas() {
+        let mut first = [0u8; 16];
+        first[15] = 1;
+        let mut second = [0u8; 16];
+        second[15] = 2;
+
+        let hex = format!("{},{}", HEXLOWER.encode(&first), HEXLOWER.encode(&second));
+        let context = parse_context(&hex).expect("two valid 16-byte hex ids");
+        assert_eq!(context, vec![first, second]);
+    }
+
+    #[test]
+    fn parse_context_of_empty_string_is_no_context() {
+        assert_eq!(parse_context("").expect("empty context is valid"), Vec::new());
+    }
+
+    #[test]
+    fn parse_context_rejects_invalid_hex() {
+        assert!(parse_context("not-hex").is_err());
+    }
+
+    #[test]
+    fn parse_context_rejects_wrong_length_hex() {
+        // Valid hex, but only 8 bytes instead of the 16 a VersionId needs.
+        assert!(parse_context("0000000000000000").is_err());
+    }
+
+    #[test]
+    fn format_parse_accepts_human_and_json() {
+        assert_eq!(Format::parse("human").unwrap(), Format::Human);
+        assert_eq!(Format::parse("json").unwrap(), Format::Json);
+    }
+
+    #[test]
+    fn format_parse_rejects_unknown_values() {
+        assert!(Format::parse("xml").is_err());
+    }
+}
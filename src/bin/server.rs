@@ -1,55 +1,155 @@
 use std::{
     collections::HashMap,
     net::SocketAddr,
-    sync::{Arc, Mutex, OnceLock},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    time::Duration,
 };
 
+use clap::{arg, command};
 use data_encoding::BASE32_NOPAD;
-use dns_kv::Message;
+use dns_kv::{
+    persistence::{self, Database, Operation, Store},
+    Message, SiblingSet, VersionId,
+};
+use rand::Rng;
 
-use tokio::net::UdpSocket;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
 
 use simple_dns::{
     rdata::{RData, A, AAAA, TXT},
-    Packet, Question, ResourceRecord, CLASS, QTYPE, TYPE,
+    Name, Packet, PacketFlag, Question, ResourceRecord, CLASS, QTYPE, TYPE,
 };
 
 // Easy mode error handling.
 type Result<T> = core::result::Result<T, Error>;
 type Error = Box<dyn std::error::Error>;
 
-type Database = HashMap<String, String>;
+/// Classic DNS-over-UDP datagrams are assumed safe up to this size (no
+/// EDNS0 opt-in anywhere in this protocol). A reply that would exceed it is
+/// instead sent with no answers and the TC bit set, telling the client to
+/// retry the same query over TCP, where the 2-byte length prefix allows
+/// replies of up to 65535 bytes.
+const MAX_UDP_REPLY: usize = 512;
+
+/// Which transport a query arrived over, so `process_query` knows whether
+/// it's allowed to truncate an oversized reply.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
 
-static DATABASE: OnceLock<Mutex<Database>> = OnceLock::new();
+/// Ephemeral scratch space for in-flight chunked uploads: the AAAA/A query
+/// staging buckets clients assemble a base32-encoded `Message` into before
+/// the final A query commits it. Never persisted — if the server restarts
+/// mid-upload, the chunks staged so far are lost and the upload's final A
+/// query fails; `exchange` only resends single queries, not the upload.
+type Staging = HashMap<String, String>;
+
+/// The in-memory state, plus the on-disk store the versioned database is
+/// persisted through, guarded together so a mutation and its log record
+/// never drift apart.
+struct AppState {
+    staging: Staging,
+    /// The durable, versioned key/value store and owner table clients read
+    /// and write through TXT/A queries — reconstructed from the snapshot
+    /// and log on every `Store::open`, so ownership survives a restart.
+    db: Database,
+    store: Store,
+}
 
-fn get_value(key: &String) -> Option<String> {
-    let mut db = DATABASE
-        .get()
-        .expect("Database not initialized")
-        .lock()
-        .expect("Failed to lock database");
-    db.remove(key)
+/// Checks that `pubkey` is allowed to write `key`: the first writer of a
+/// key becomes its owner, and only that owner may write it afterwards.
+fn check_owner(state: &mut AppState, key: &str, pubkey: &[u8; 32]) -> bool {
+    match state.db.owners.get(key) {
+        Some(owner) => owner == pubkey,
+        None => {
+            state.db.owners.insert(key.to_string(), *pubkey);
+            true
+        }
+    }
 }
 
-fn set_value(key: String, value: String) {
-    let mut db = DATABASE
+static STATE: OnceLock<Mutex<AppState>> = OnceLock::new();
+
+fn state() -> MutexGuard<'static, AppState> {
+    STATE
         .get()
-        .expect("Database not initialized")
+        .expect("State not initialized")
         .lock()
-        .expect("Failed to lock database");
-    db.insert(key, value);
+        .expect("Failed to lock state")
+}
+
+fn get_value(key: &String) -> Option<String> {
+    state().staging.remove(key)
 }
 
 fn append_value(key: String, value: String) {
-    let mut db = DATABASE
-        .get()
-        .expect("Database not initialized")
-        .lock()
-        .expect("Failed to lock database");
-    let mut current_value = db.remove(&key).unwrap_or_default();
+    let mut state = state();
+    let mut current_value = state.staging.remove(&key).unwrap_or_default();
     current_value.push_str(&value);
     tracing::debug!("{} {}", key.clone(), current_value.clone());
-    db.insert(key, current_value);
+    state.staging.insert(key, current_value);
+}
+
+/// Reads every sibling currently live for `key` without consuming them, so
+/// repeated polling is idempotent until a new write actually changes them.
+fn read_siblings(key: &str) -> Vec<(VersionId, String)> {
+    state().db.versions.get(key).cloned().unwrap_or_default()
+}
+
+/// Applies a causal write to the versioned store: drops every sibling whose
+/// `VersionId` appears in `context` (it is now superseded), and adds
+/// `value` under a freshly generated `VersionId`. A write with an empty
+/// context is blind and is simply added as a new sibling. `pubkey` is
+/// recorded as `key`'s owner if it doesn't have one yet, so ownership
+/// survives a restart exactly the way the versioned values do.
+fn write_version(key: &str, value: String, context: &[VersionId], pubkey: [u8; 32]) {
+    let mut state = state();
+    let version: VersionId = rand::rng().random();
+
+    let op = Operation::Write {
+        key: key.to_string(),
+        version,
+        value,
+        context: context.to_vec(),
+        pubkey,
+    };
+    persistence::apply(&mut state.db, &op);
+
+    if let Err(e) = state.store.append(&op) {
+        tracing::warn!("failed to persist operation: {e:?}");
+    }
+}
+
+/// Encodes the current siblings for `key` the same way a `Message` is
+/// encoded for upload: bincode then base32, ready to stream back over TXT.
+fn encode_siblings(key: &str) -> Result<String> {
+    let siblings = read_siblings(key);
+    let blob = bincode::serialize(&SiblingSet { siblings })?;
+    Ok(BASE32_NOPAD.encode(&blob))
+}
+
+/// Builds the A-record acknowledgment clients look for after an A-query
+/// commit: `41.41.41.41` if the write was accepted, `0.0.0.0` if it was
+/// rejected (bad signature, wrong owner, or a protocol version mismatch).
+/// A rejection is a normal business-logic answer, not a dropped packet —
+/// sending one instead of erroring out of `process_query` means the client
+/// finds out immediately instead of timing out and retrying for nothing.
+fn ack(qname: Name<'_>, accepted: bool) -> ResourceRecord<'_> {
+    let address = if accepted { [41, 41, 41, 41] } else { [0, 0, 0, 0] };
+    ResourceRecord::new(
+        qname,
+        CLASS::IN,
+        2,
+        RData::A(A {
+            address: u32::from_be_bytes(address),
+        }),
+    )
 }
 
 async fn parse_a_query(q: Question<'_>) -> Result<ResourceRecord<'_>> {
@@ -60,17 +160,31 @@ async fn parse_a_query(q: Question<'_>) -> Result<ResourceRecord<'_>> {
     let decoded = BASE32_NOPAD.decode(value.as_bytes())?;
     let msg: Message = bincode::deserialize(&decoded)?;
 
-    set_value(msg.key.clone().to_uppercase(), value);
+    if msg.version != dns_kv::PROTOCOL_VERSION {
+        tracing::warn!(
+            "rejected message for {}: protocol version {} != {}",
+            msg.key,
+            msg.version,
+            dns_kv::PROTOCOL_VERSION
+        );
+        return Ok(ack(q.qname, false));
+    }
 
-    tracing::info!("Set value {} {}", key.to_uppercase(), msg.key);
-    Ok(ResourceRecord::new(
-        q.qname,
-        CLASS::IN,
-        2,
-        RData::A(A {
-            address: u32::from_be_bytes([41, 41, 41, 41]),
-        }),
-    ))
+    if !msg.verify() {
+        tracing::warn!("rejected message for {} with invalid signature", msg.key);
+        return Ok(ack(q.qname, false));
+    }
+
+    let target_key = msg.key.to_uppercase();
+    if !check_owner(&mut state(), &target_key, &msg.pubkey) {
+        tracing::warn!("rejected write to {target_key}: owned by a different key");
+        return Ok(ack(q.qname, false));
+    }
+
+    write_version(&target_key, msg.value, &msg.context, msg.pubkey);
+
+    tracing::info!("Set value {} {}", key.to_uppercase(), target_key);
+    Ok(ack(q.qname, true))
 }
 
 async fn parse_aaaa_query(q: Question<'_>) -> Result<ResourceRecord<'_>> {
@@ -94,27 +208,21 @@ async fn parse_aaaa_query(q: Question<'_>) -> Result<ResourceRecord<'_>> {
     ))
 }
 
+/// Builds a TXT answer carrying the *entire* current value for `name`,
+/// split across as many 255-byte character-strings as it takes — a single
+/// TXT record can hold many, so this is one answer regardless of size.
 async fn parse_txt_query(q: Question<'_>) -> Result<ResourceRecord<'_>> {
     let name = q.qname.to_string().to_uppercase();
     tracing::info!("Lookup {}", name);
 
-    let value = get_value(&name).unwrap_or("AAAA".to_string());
-
-    let len = value.clone().len().min(255);
-    let (txt, remainder) = value.split_at(len);
-
-    tracing::info!("Got value {}", value);
-
-    if !remainder.is_empty() {
-        set_value(name.clone(), remainder.to_string());
-    } else {
-        tracing::info!("No remaining info");
-    };
-
-    tracing::info!("returning {}", &value);
+    let value = encode_siblings(&name)?;
 
     let mut data = TXT::new();
-    data.add_char_string(txt.to_string().try_into()?);
+    for chunk in value.as_bytes().chunks(255) {
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+        data.add_char_string(chunk.try_into()?);
+    }
+
     Ok(ResourceRecord::new(
         q.qname.clone(),
         CLASS::IN,
@@ -123,10 +231,14 @@ async fn parse_txt_query(q: Question<'_>) -> Result<ResourceRecord<'_>> {
     ))
 }
 
-async fn handle_dns_query(socket: Arc<UdpSocket>, data: Vec<u8>, peer: SocketAddr) -> Result<()> {
-    let packet = Packet::parse(&data)?;
+async fn process_query(data: &[u8], transport: Transport) -> Result<Vec<u8>> {
+    let packet = Packet::parse(data)?;
 
     let mut response = packet.clone().into_reply();
+    // Built up front, before `packet.questions` is moved out below, so it's
+    // still available as the empty TC fallback if `response` turns out to
+    // be too big to send over UDP.
+    let mut truncated = packet.clone().into_reply();
 
     for q in packet.questions {
         let answer = match q.qtype {
@@ -139,12 +251,54 @@ async fn handle_dns_query(socket: Arc<UdpSocket>, data: Vec<u8>, peer: SocketAdd
         response.answers.push(answer);
     }
 
-    let rd = response.build_bytes_vec()?;
+    let bytes = response.build_bytes_vec()?;
+
+    // Over UDP, a reply too big for a bare datagram is sent empty with the
+    // TC bit set instead, so the client knows to retry over TCP — where
+    // the full value fits in one reply of up to 65535 bytes.
+    if transport == Transport::Udp && bytes.len() > MAX_UDP_REPLY {
+        truncated.set_flags(PacketFlag::TRUNCATION);
+        return Ok(truncated.build_bytes_vec()?);
+    }
+
+    Ok(bytes)
+}
+
+async fn handle_dns_query(socket: Arc<UdpSocket>, data: Vec<u8>, peer: SocketAddr) -> Result<()> {
+    let rd = process_query(&data, Transport::Udp).await?;
     socket.send_to(&rd, peer).await?;
 
     Ok(())
 }
 
+async fn handle_tcp_connection(stream: TcpStream) -> Result<()> {
+    let (read_half, write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let mut writer = BufWriter::new(write_half);
+
+    loop {
+        let mut len_buf = [0u8; 2];
+        if reader.read_exact(&mut len_buf).await.is_err() {
+            // Peer closed the connection; nothing left to serve.
+            break;
+        }
+        let len = u16::from_be_bytes(len_buf) as usize;
+
+        let mut data = vec![0u8; len];
+        reader.read_exact(&mut data).await?;
+
+        let rd = process_query(&data, Transport::Tcp).await?;
+        // A reply too long for the 2-byte length prefix is not sent; the
+        // connection is closed instead.
+        let rd_len = u16::try_from(rd.len())?;
+        writer.write_all(&rd_len.to_be_bytes()).await?;
+        writer.write_all(&rd).await?;
+        writer.flush().await?;
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -155,7 +309,67 @@ async fn main() -> Result<()> {
         )))
         .try_init()?;
 
-    DATABASE.get_or_init(|| Mutex::new(HashMap::new()));
+    let matches = command!()
+        .arg(arg!(--nosave "Run purely in-memory, with no disk footprint"))
+        .arg(
+            arg!(--"data-dir" <DIR> "Directory for the snapshot and operation log")
+                .default_value("./data"),
+        )
+        .get_matches();
+
+    let data_dir = (!matches.get_flag("nosave"))
+        .then(|| PathBuf::from(matches.get_one::<String>("data-dir").expect("has default")));
+
+    let (db, store) = Store::open(data_dir)?;
+    STATE.get_or_init(|| {
+        Mutex::new(AppState {
+            staging: HashMap::new(),
+            db,
+            store,
+        })
+    });
+
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        interval.tick().await; // first tick fires immediately
+        loop {
+            interval.tick().await;
+            let mut state = state();
+            if let Err(e) = state.store.snapshot(&state.db) {
+                tracing::warn!("failed to snapshot database: {e:?}");
+            }
+        }
+    });
+
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            tracing::info!("shutting down, snapshotting database");
+            let mut state = state();
+            if let Err(e) = state.store.snapshot(&state.db) {
+                tracing::warn!("failed to snapshot database on shutdown: {e:?}");
+            }
+            std::process::exit(0);
+        }
+    });
+
+    let tcp_listener = TcpListener::bind("0.0.0.0:5353").await?;
+    tracing::info!("listening on tcp {}", tcp_listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        loop {
+            match tcp_listener.accept().await {
+                Ok((stream, peer)) => {
+                    tracing::debug!("accepted tcp connection from {}", peer);
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_tcp_connection(stream).await {
+                            tracing::debug!("{e:?}");
+                        }
+                    });
+                }
+                Err(e) => tracing::debug!("tcp accept error: {e:?}"),
+            }
+        }
+    });
 
     let socket = UdpSocket::bind("0.0.0.0:5353").await?;
     tracing::info!("listening on {}", socket.local_addr().unwrap());
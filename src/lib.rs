@@ -1,7 +1,123 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
+pub mod persistence;
+
+/// Identifies one causal version of a key's value. Generated fresh by the
+/// server on every accepted write.
+pub type VersionId = [u8; 16];
+
+/// The wire protocol version this build of `dns_kv` speaks. Bump whenever
+/// `Message`'s fields change in a way that would make old and new builds
+/// silently misinterpret each other's bincode bytes.
+pub const PROTOCOL_VERSION: u8 = 1;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
     pub key: String,
     pub value: String,
+    pub pubkey: [u8; 32],
+    pub signature: [u8; 64],
+    /// The `VersionId`s this write observed (e.g. from a prior `get`).
+    /// Siblings listed here are superseded by this write; an empty context
+    /// is a blind write that is simply added alongside whatever is there.
+    pub context: Vec<VersionId>,
+    /// The `PROTOCOL_VERSION` this message was built against.
+    pub version: u8,
+}
+
+impl Message {
+    /// Builds a `Message` for `key`/`value`/`context`, signed with
+    /// `signing_key` so the server can verify the writer's identity before
+    /// committing it.
+    pub fn signed(
+        key: String,
+        value: String,
+        context: Vec<VersionId>,
+        signing_key: &SigningKey,
+    ) -> bincode::Result<Message> {
+        let payload = signing_payload(&key, &value, &context, PROTOCOL_VERSION)?;
+        let signature = signing_key.sign(&payload);
+        Ok(Message {
+            key,
+            value,
+            pubkey: signing_key.verifying_key().to_bytes(),
+            signature: signature.to_bytes(),
+            context,
+            version: PROTOCOL_VERSION,
+        })
+    }
+
+    /// Verifies that `signature` was produced by `pubkey` over this
+    /// message's `(key, value, context, version)`.
+    pub fn verify(&self) -> bool {
+        let Ok(payload) =
+            signing_payload(&self.key, &self.value, &self.context, self.version)
+        else {
+            return false;
+        };
+        let Ok(verifying_key) = VerifyingKey::from_bytes(&self.pubkey) else {
+            return false;
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        verifying_key.verify(&payload, &signature).is_ok()
+    }
+}
+
+/// The bytes a `Message`'s signature is computed over: the bincode encoding
+/// of `(key, value, context, version)`, excluding the pubkey/signature
+/// fields themselves.
+fn signing_payload(
+    key: &str,
+    value: &str,
+    context: &[VersionId],
+    version: u8,
+) -> bincode::Result<Vec<u8>> {
+    bincode::serialize(&(key, value, context, version))
+}
+
+/// The wire format for a TXT read reply: every sibling currently live for
+/// the queried key. The set of `VersionId`s here doubles as the opaque
+/// causal context a client should echo back on its next write.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SiblingSet {
+    pub siblings: Vec<(VersionId, String)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_message_verifies() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let message =
+            Message::signed("k".to_string(), "v".to_string(), Vec::new(), &signing_key)
+                .expect("bincode encoding of a plain message never fails");
+
+        assert!(message.verify());
+    }
+
+    #[test]
+    fn tampered_value_fails_verification() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let mut message =
+            Message::signed("k".to_string(), "v".to_string(), Vec::new(), &signing_key)
+                .expect("bincode encoding of a plain message never fails");
+
+        message.value = "tampered".to_string();
+        assert!(!message.verify());
+    }
+
+    #[test]
+    fn verification_fails_under_a_different_signer() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let other_key = SigningKey::from_bytes(&[9u8; 32]);
+        let mut message =
+            Message::signed("k".to_string(), "v".to_string(), Vec::new(), &signing_key)
+                .expect("bincode encoding of a plain message never fails");
+
+        message.pubkey = other_key.verifying_key().to_bytes();
+        assert!(!message.verify());
+    }
 }
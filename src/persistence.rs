@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::VersionId;
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const LOG_FILE: &str = "oplog.bin";
+
+/// The durable, versioned key/value store: each key maps to the set of
+/// siblings (`VersionId`, value) currently live for it.
+pub type VersionedDatabase = HashMap<String, Vec<(VersionId, String)>>;
+
+/// The Ed25519 public key that first wrote each key, so later writes from a
+/// different key can be rejected instead of clobbering it.
+pub type OwnerTable = HashMap<String, [u8; 32]>;
+
+/// Everything the server persists: the versioned values, and the owner that
+/// first claimed each key. Bundled together so a snapshot/replay can never
+/// reconstruct one without the other.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Database {
+    pub versions: VersionedDatabase,
+    pub owners: OwnerTable,
+}
+
+/// A single causal write, appended to the on-disk log so it can be replayed
+/// after a restart. Supersedes every sibling listed in `context` and adds
+/// `(version, value)` as a new one; an empty `context` is a blind write
+/// that simply adds a sibling alongside whatever is already there. Carries
+/// the writer's `pubkey` so replay can reconstruct key ownership, not just
+/// values.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Operation {
+    Write {
+        key: String,
+        version: VersionId,
+        value: String,
+        context: Vec<VersionId>,
+        pubkey: [u8; 32],
+    },
+}
+
+/// Applies `op` to `db` in place. Used both by callers recording a fresh
+/// mutation and by log replay reconstructing state from disk. Ownership is
+/// first-writer-wins: if `key` already has an owner, `pubkey` is ignored.
+/// Idempotent: applying the same `version` to a key that already carries it
+/// as a sibling is a no-op, so replaying an operation that's already
+/// reflected in a snapshot (e.g. after a crash mid-`Store::snapshot`) can't
+/// produce a phantom duplicate sibling.
+pub fn apply(db: &mut Database, op: &Operation) {
+    match op {
+        Operation::Write {
+            key,
+            version,
+            value,
+            context,
+            pubkey,
+        } => {
+            db.owners.entry(key.clone()).or_insert(*pubkey);
+
+            let siblings = db.versions.entry(key.clone()).or_default();
+            siblings.retain(|(id, _)| !context.contains(id));
+            if !siblings.iter().any(|(id, _)| id == version) {
+                siblings.push((*version, value.clone()));
+            }
+        }
+    }
+}
+
+/// The on-disk half of the database: a full snapshot plus an append-only
+/// log of operations since that snapshot. `None` data dir means purely
+/// in-memory, no-footprint operation (e.g. `--nosave`).
+pub struct Store {
+    data_dir: Option<PathBuf>,
+    log: Option<BufWriter<File>>,
+}
+
+impl Store {
+    /// Loads the snapshot (if any) under `data_dir`, replays the trailing
+    /// log on top of it, and returns the reconstructed database alongside
+    /// a `Store` handle for persisting further mutations. Pass `None` to
+    /// skip disk entirely.
+    pub fn open(data_dir: Option<PathBuf>) -> std::io::Result<(Database, Store)> {
+        let Some(dir) = data_dir else {
+            return Ok((
+                Database::default(),
+                Store {
+                    data_dir: None,
+                    log: None,
+                },
+            ));
+        };
+
+        std::fs::create_dir_all(&dir)?;
+
+        let mut db = Self::load_snapshot(&dir.join(SNAPSHOT_FILE))?;
+        Self::replay_log(&dir.join(LOG_FILE), &mut db)?;
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+
+        Ok((
+            db,
+            Store {
+                data_dir: Some(dir),
+                log: Some(BufWriter::new(log)),
+            },
+        ))
+    }
+
+    fn load_snapshot(path: &Path) -> std::io::Result<Database> {
+        if !path.exists() {
+            return Ok(Database::default());
+        }
+        let file = File::open(path)?;
+        bincode::deserialize_from(BufReader::new(file))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    fn replay_log(path: &Path, db: &mut Database) -> std::io::Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        loop {
+            match bincode::deserialize_from::<_, Operation>(&mut reader) {
+                Ok(op) => apply(db, &op),
+                // A deserialize failure means we've hit the end of the log
+                // (or a partially-written trailing record from a crash).
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends `op` to the log. No-op when running without a data dir.
+    pub fn append(&mut self, op: &Operation) -> std::io::Result<()> {
+        let Some(log) = &mut self.log else {
+            return Ok(());
+        };
+        bincode::serialize_into(&mut *log, op)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        log.flush()
+    }
+
+    /// Folds `db` into a fresh snapshot file and truncates the log, so the
+    /// log only ever holds operations made since the last snapshot. The
+    /// rename and the truncate are two separate filesystem operations, not
+    /// one atomic unit — a crash between them replays ops on startup that
+    /// are already folded into the new snapshot, but `apply` is idempotent
+    /// for exactly this reason, so that replay is harmless.
+    pub fn snapshot(&mut self, db: &Database) -> std::io::Result<()> {
+        let Some(dir) = &self.data_dir else {
+            return Ok(());
+        };
+
+        let tmp_path = dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+        bincode::serialize_into(BufWriter::new(File::create(&tmp_path)?), db)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        std::fs::rename(&tmp_path, dir.join(SNAPSHOT_FILE))?;
+
+        let log = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(dir.join(LOG_FILE))?;
+        self.log = Some(BufWriter::new(log));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_op(key: &str, version: VersionId, value: &str, context: Vec<VersionId>) -> Operation {
+        Operation::Write {
+            key: key.to_string(),
+            version,
+            value: value.to_string(),
+            context,
+            pubkey: [1u8; 32],
+        }
+    }
+
+    #[test]
+    fn blind_write_adds_a_sibling_alongside_existing_ones() {
+        let mut db = Database::default();
+        apply(&mut db, &write_op("k", [1; 16], "a", Vec::new()));
+        apply(&mut db, &write_op("k", [2; 16], "b", Vec::new()));
+
+        let siblings = &db.versions["k"];
+        assert_eq!(siblings.len(), 2);
+        assert!(siblings.contains(&([1; 16], "a".to_string())));
+        assert!(siblings.contains(&([2; 16], "b".to_string())));
+    }
+
+    #[test]
+    fn replaying_the_same_operation_twice_is_idempotent() {
+        let mut db = Database::default();
+        let op = write_op("k", [1; 16], "a", Vec::new());
+        apply(&mut db, &op);
+        apply(&mut db, &op);
+
+        assert_eq!(db.versions["k"], vec![([1; 16], "a".to_string())]);
+    }
+
+    #[test]
+    fn write_with_context_supersedes_the_observed_siblings() {
+        let mut db = Database::default();
+        apply(&mut db, &write_op("k", [1; 16], "a", Vec::new()));
+        apply(&mut db, &write_op("k", [2; 16], "b", vec![[1; 16]]));
+
+        assert_eq!(db.versions["k"], vec![([2; 16], "b".to_string())]);
+    }
+
+    #[test]
+    fn first_writer_becomes_the_recorded_owner() {
+        let mut db = Database::default();
+        apply(&mut db, &write_op("k", [1; 16], "a", Vec::new()));
+
+        let mut second = write_op("k", [2; 16], "b", Vec::new());
+        if let Operation::Write { pubkey, .. } = &mut second {
+            *pubkey = [2u8; 32];
+        }
+        apply(&mut db, &second);
+
+        assert_eq!(db.owners["k"], [1u8; 32]);
+    }
+
+    #[test]
+    fn store_open_with_no_data_dir_is_purely_in_memory() {
+        let (db, mut store) = Store::open(None).expect("in-memory open never fails");
+        assert!(db.versions.is_empty());
+        assert!(db.owners.is_empty());
+
+        let op = write_op("k", [1; 16], "a", Vec::new());
+        store
+            .append(&op)
+            .expect("append is a no-op without a data dir");
+        store
+            .snapshot(&db)
+            .expect("snapshot is a no-op without a data dir");
+    }
+}